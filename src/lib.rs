@@ -1,5 +1,7 @@
 use std::io::prelude::*;
 
+mod mtd;
+
 #[derive(Debug)]
 pub enum FwError {
     Io(std::io::Error),
@@ -10,13 +12,22 @@ pub enum FwError {
     WrongDevNum(usize),
     BadCrc,
     EnvVarSyntax(String),
-    Scan(scan_fmt::parse::ScanError)
+    Scan(scan_fmt::parse::ScanError),
+    NotPresent,
+    NotUnicode(Vec<u8>),
+    EnvTooLarge { have: usize, max: usize },
 }
 
 impl std::fmt::Display for FwError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             FwError::Io(err) => err.fmt(f),
+            FwError::NotPresent => write!(f, "environment variable not found"),
+            FwError::NotUnicode(_) => write!(f, "environment variable was not valid unicode"),
+            FwError::EnvTooLarge { have, max } => write!(
+                f,
+                "environment is {have} bytes, which doesn't fit in the {max} bytes the configured block has room for after its header"
+            ),
             _ => write!(f, "Parsing trouble"),
         }
     }
@@ -53,6 +64,17 @@ pub struct ConfigLine {
     pub devname: String,
     pub start: usize,
     pub size: usize,
+    pub endianness: Endianness,
+}
+
+/// Byte order the environment's CRC header is stored in. U-Boot writes it
+/// in the *target's* byte order, so cross-reading an image built for
+/// another arch (or a big-endian device) needs this set explicitly.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Endianness {
+    #[default]
+    Little,
+    Big,
 }
 
 impl std::str::FromStr for ConfigLine {
@@ -67,6 +89,7 @@ impl std::str::FromStr for ConfigLine {
             devname,
             start,
             size,
+            endianness: Endianness::default(),
         })
     }
 }
@@ -106,35 +129,52 @@ impl Config {
 
 #[derive(Debug)]
 pub struct FwEnv {
-    pub vars: Vec<(Vec<u8>, Vec<u8>)>,
+    pub vars: EnvVars,
+    active_copy: ActiveCopy,
+}
+
+type EnvVars = Vec<(Vec<u8>, Vec<u8>)>;
+
+// Which block `read` picked as authoritative. In the redundant case this
+// also carries the flag byte that was read off that copy, so `write` can
+// increment it for the other copy and flip which one is active.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ActiveCopy {
+    Single,
+    First(u8),
+    Second(u8),
 }
 
 const ENV_SIMPLE_SIZE: usize = std::mem::size_of::<u32>();
 const ENV_REDUNDANT_SIZE: usize = std::mem::size_of::<u32>() + std::mem::size_of::<u8>();
 
 impl FwEnv {
-    // TODO: skip bad blocks on flash
-    // this will probably involve linux-specific syscalls ("nix" crate)
     fn read_block<P: AsRef<std::path::Path>>(
         path: P,
         offset: usize,
         size: usize,
     ) -> Result<Vec<u8>, FwError> {
+        if mtd::is_mtd_char_device(&path) {
+            return mtd::read_block(path, offset, size);
+        }
         let mut buf = vec![0; size];
         let mut file = std::fs::File::open(path)?;
         file.seek(std::io::SeekFrom::Start(offset as u64))?;
         file.read_exact(&mut buf)?;
         Ok(buf)
     }
-    // TODO: understand what the flag means, and do the whole flag dance
-    // to read the appropriate block in case of redundancy
-    pub fn read(config: &Config) -> Result<Self, FwError> {
-        let block = Self::read_block(&config.line1.devname, config.line1.start, config.line1.size)?;
-        let refcrc: u32 = unsafe { std::mem::transmute([block[0], block[1], block[2], block[3]]) };
-        let skipped_bytes = if config.is_redundant() {
-            ENV_REDUNDANT_SIZE
-        } else {
-            ENV_SIMPLE_SIZE
+    // Validates a block's CRC and parses the "key=value\0" entries after
+    // `skipped_bytes` (the header). Used for both the simple and the
+    // redundant (per-copy) layouts.
+    fn parse_block(
+        block: &[u8],
+        skipped_bytes: usize,
+        endianness: Endianness,
+    ) -> Result<EnvVars, FwError> {
+        let crc_bytes = [block[0], block[1], block[2], block[3]];
+        let refcrc: u32 = match endianness {
+            Endianness::Little => u32::from_le_bytes(crc_bytes),
+            Endianness::Big => u32::from_be_bytes(crc_bytes),
         };
         let crc = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
         let mut digest = crc.digest();
@@ -154,7 +194,49 @@ impl FwEnv {
                 .ok_or_else(|| FwError::EnvVarSyntax(String::from_utf8_lossy(s).to_string()))?;
             vars.push((s[..pos].to_vec(), s[pos + 1..].to_vec()));
         }
-        Ok(Self { vars })
+        Ok(vars)
+    }
+    // Reads and validates a single redundant copy, returning its vars
+    // together with the flag byte that sits right after its CRC. A failure
+    // to even physically read the block (bad blocks exhausted, device
+    // momentarily gone, ...) is folded into the same error path as a CRC
+    // mismatch, so the caller can fall back to the other copy either way.
+    fn read_copy(line: &ConfigLine) -> Result<(EnvVars, u8), FwError> {
+        let block = Self::read_block(&line.devname, line.start, line.size)?;
+        let vars = Self::parse_block(&block, ENV_REDUNDANT_SIZE, line.endianness)?;
+        Ok((vars, block[4]))
+    }
+    pub fn read(config: &Config) -> Result<Self, FwError> {
+        let line2 = match &config.line2 {
+            None => {
+                let block =
+                    Self::read_block(&config.line1.devname, config.line1.start, config.line1.size)?;
+                let vars = Self::parse_block(&block, ENV_SIMPLE_SIZE, config.line1.endianness)?;
+                return Ok(Self {
+                    vars,
+                    active_copy: ActiveCopy::Single,
+                });
+            }
+            Some(line2) => line2,
+        };
+        let copy1 = Self::read_copy(&config.line1);
+        let copy2 = Self::read_copy(line2);
+        let (vars, active_copy) = match (copy1, copy2) {
+            (Ok((vars1, flag1)), Ok((vars2, flag2))) => {
+                // The flag byte is an incrementing generation counter (with
+                // 0xff -> 0x00 wraparound counting as newer); copy 2 is only
+                // active when it's exactly one generation ahead of copy 1.
+                if flag2 == flag1.wrapping_add(1) {
+                    (vars2, ActiveCopy::Second(flag2))
+                } else {
+                    (vars1, ActiveCopy::First(flag1))
+                }
+            }
+            (Ok((vars1, flag1)), Err(_)) => (vars1, ActiveCopy::First(flag1)),
+            (Err(_), Ok((vars2, flag2))) => (vars2, ActiveCopy::Second(flag2)),
+            (Err(err), Err(_)) => return Err(err),
+        };
+        Ok(Self { vars, active_copy })
     }
     pub fn find_var<'a, 'b>(&'a self, name: impl Into<&'b [u8]>) -> Option<&'a [u8]> {
         let name = name.into();
@@ -163,6 +245,132 @@ impl FwEnv {
             .find(|(v, _)| v[..] == name[..])
             .map(|(_, t)| &t[..])
     }
+    /// Looks up `name` and decodes its value as UTF-8, mirroring
+    /// `std::env::var`.
+    pub fn var(&self, name: &str) -> Result<String, FwError> {
+        let bytes = self.find_var(name.as_bytes()).ok_or(FwError::NotPresent)?;
+        String::from_utf8(bytes.to_vec()).map_err(|err| FwError::NotUnicode(err.into_bytes()))
+    }
+    /// Like [`FwEnv::var`], but process environment variables of the same
+    /// name take priority over the stored firmware value when set, letting
+    /// a caller opt in to overriding firmware defaults at runtime.
+    pub fn var_overlaid(&self, name: &str) -> Result<String, FwError> {
+        if let Ok(value) = std::env::var(name) {
+            return Ok(value);
+        }
+        self.var(name)
+    }
+    /// All stored variables decoded as UTF-8, mirroring `std::env::vars`.
+    /// Entries whose key or value aren't valid UTF-8 are silently skipped;
+    /// use [`FwEnv::iter`] to see the raw bytes instead. Named distinctly
+    /// from the [`FwEnv::vars`] field, which holds the raw, lossless bytes.
+    pub fn vars_lossy(&self) -> impl Iterator<Item = (String, String)> + '_ {
+        self.vars.iter().filter_map(|(k, v)| {
+            let k = String::from_utf8(k.clone()).ok()?;
+            let v = String::from_utf8(v.clone()).ok()?;
+            Some((k, v))
+        })
+    }
+    /// All stored variables as raw byte pairs.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
+        self.vars.iter().map(|(k, v)| (&k[..], &v[..]))
+    }
+    pub fn set_var(&mut self, name: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) {
+        let name = name.into();
+        match self.vars.iter_mut().find(|(v, _)| *v == name) {
+            Some((_, t)) => *t = value.into(),
+            None => self.vars.push((name, value.into())),
+        }
+    }
+    pub fn remove_var<'b>(&mut self, name: impl Into<&'b [u8]>) {
+        let name = name.into();
+        self.vars.retain(|(v, _)| v[..] != name[..]);
+    }
+    // Serializes `self.vars` the way U-Boot expects to find them again:
+    // "key=value\0" entries, a final empty entry (double-NUL), zero-padded
+    // to `size`, with a CRC_32_ISO_HDLC over everything after the header.
+    // `flag`, when given, is written as the generation byte of a redundant
+    // copy right after the CRC.
+    fn serialize(
+        &self,
+        size: usize,
+        flag: Option<u8>,
+        endianness: Endianness,
+    ) -> Result<Vec<u8>, FwError> {
+        let header_size = if flag.is_some() {
+            ENV_REDUNDANT_SIZE
+        } else {
+            ENV_SIMPLE_SIZE
+        };
+        let max = size.saturating_sub(header_size);
+        let mut payload = Vec::with_capacity(max);
+        for (k, v) in &self.vars {
+            payload.extend_from_slice(k);
+            payload.push(b'=');
+            payload.extend_from_slice(v);
+            payload.push(0);
+        }
+        payload.push(0);
+        if payload.len() > max {
+            return Err(FwError::EnvTooLarge {
+                have: payload.len(),
+                max,
+            });
+        }
+        payload.resize(max, 0);
+
+        let crc = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+        let mut digest = crc.digest();
+        digest.update(&payload);
+        let crc_bytes = match endianness {
+            Endianness::Little => digest.finalize().to_le_bytes(),
+            Endianness::Big => digest.finalize().to_be_bytes(),
+        };
+
+        let mut block = Vec::with_capacity(size);
+        block.extend_from_slice(&crc_bytes);
+        if let Some(flag) = flag {
+            block.push(flag);
+        }
+        block.extend_from_slice(&payload);
+        Ok(block)
+    }
+    pub fn write(&self, config: &Config) -> Result<(), FwError> {
+        // Build the whole block in memory first, so a short write can't
+        // leave the on-flash block half-corrupted.
+        let next_flag = self.initial_flag().wrapping_add(1);
+        // Write to the currently inactive copy, one generation ahead, so
+        // the block we just wrote atomically becomes the active one.
+        let (line, block) = match (&config.line2, self.active_copy) {
+            (None, _) => (
+                &config.line1,
+                self.serialize(config.line1.size, None, config.line1.endianness)?,
+            ),
+            (Some(_), ActiveCopy::Second(_)) => (
+                &config.line1,
+                self.serialize(config.line1.size, Some(next_flag), config.line1.endianness)?,
+            ),
+            (Some(line2), _) => (
+                line2,
+                self.serialize(line2.size, Some(next_flag), line2.endianness)?,
+            ),
+        };
+        if mtd::is_mtd_char_device(&line.devname) {
+            return mtd::write_block(&line.devname, line.start, &block);
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&line.devname)?;
+        file.seek(std::io::SeekFrom::Start(line.start as u64))?;
+        file.write_all(&block)?;
+        Ok(())
+    }
+    fn initial_flag(&self) -> u8 {
+        match self.active_copy {
+            ActiveCopy::First(flag) | ActiveCopy::Second(flag) => flag,
+            ActiveCopy::Single => 0,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -178,12 +386,14 @@ mod tests {
                 line1: ConfigLine {
                     devname: "/dev/mmcblk1".to_string(),
                     start: 0x180000,
-                    size: 0x20000
+                    size: 0x20000,
+                    endianness: Endianness::Little
                 },
                 line2: Some(ConfigLine {
                     devname: "/dev/mmcblk1".to_string(),
                     start: 0x1A0000,
-                    size: 0x20000
+                    size: 0x20000,
+                    endianness: Endianness::Little
                 })
             }
         );
@@ -201,7 +411,270 @@ mod tests {
         let mut config = Config::from_file("testfiles/fw_env.config").unwrap();
         config.line1.devname = "testfiles/fw_env_gt187908".to_string();
         config.line1.start = 0;
+        config.line2 = config.line2.map(|mut line2| {
+            line2.devname = "testfiles/fw_env_gt187908".to_string();
+            line2.start = 0;
+            line2
+        });
         let env = FwEnv::read(&config).unwrap();
         assert_eq!(env.find_var(&b"version_os_b"[..]), Some(&b"20181217"[..]));
     }
+
+    // A temp file that's removed when it goes out of scope, so tests that
+    // craft their own blocks don't leave litter behind.
+    struct TempFile(std::path::PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, contents: &[u8]) -> Self {
+            use std::sync::atomic::{AtomicUsize, Ordering};
+            static COUNTER: AtomicUsize = AtomicUsize::new(0);
+            let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+            let path = std::env::temp_dir().join(format!(
+                "fw_env_test_{}_{}_{}",
+                std::process::id(),
+                n,
+                name
+            ));
+            std::fs::write(&path, contents).unwrap();
+            Self(path)
+        }
+        fn line(&self, size: usize) -> ConfigLine {
+            ConfigLine {
+                devname: self.0.to_str().unwrap().to_string(),
+                start: 0,
+                size,
+                endianness: Endianness::Little,
+            }
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    // Builds a redundant copy's on-flash bytes: `[crc][flag][payload...]`,
+    // zero-padded to `size`, with a correct CRC over the payload.
+    fn make_redundant_block(vars: &[(&str, &str)], flag: u8, size: usize) -> Vec<u8> {
+        let mut payload = Vec::new();
+        for (k, v) in vars {
+            payload.extend_from_slice(k.as_bytes());
+            payload.push(b'=');
+            payload.extend_from_slice(v.as_bytes());
+            payload.push(0);
+        }
+        payload.push(0);
+        assert!(
+            payload.len() <= size - ENV_REDUNDANT_SIZE,
+            "test fixture vars don't fit in {size} bytes"
+        );
+        payload.resize(size - ENV_REDUNDANT_SIZE, 0);
+        let crc = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+        let mut digest = crc.digest();
+        digest.update(&payload);
+        let mut block = Vec::with_capacity(size);
+        block.extend_from_slice(&digest.finalize().to_le_bytes());
+        block.push(flag);
+        block.extend_from_slice(&payload);
+        block
+    }
+
+    #[test]
+    fn test_fwenv_read_picks_newer_generation() {
+        const SIZE: usize = 0x1000;
+        let copy1 = TempFile::new("copy1", &make_redundant_block(&[("foo", "one")], 3, SIZE));
+        let copy2 = TempFile::new("copy2", &make_redundant_block(&[("foo", "two")], 4, SIZE));
+        let config = Config {
+            line1: copy1.line(SIZE),
+            line2: Some(copy2.line(SIZE)),
+        };
+        let env = FwEnv::read(&config).unwrap();
+        assert_eq!(env.active_copy, ActiveCopy::Second(4));
+        assert_eq!(env.var("foo").unwrap(), "two");
+    }
+
+    #[test]
+    fn test_fwenv_read_falls_back_to_first_when_not_newer() {
+        const SIZE: usize = 0x1000;
+        let copy1 = TempFile::new("copy1", &make_redundant_block(&[("foo", "one")], 5, SIZE));
+        let copy2 = TempFile::new("copy2", &make_redundant_block(&[("foo", "two")], 5, SIZE));
+        let config = Config {
+            line1: copy1.line(SIZE),
+            line2: Some(copy2.line(SIZE)),
+        };
+        let env = FwEnv::read(&config).unwrap();
+        assert_eq!(env.active_copy, ActiveCopy::First(5));
+        assert_eq!(env.var("foo").unwrap(), "one");
+    }
+
+    #[test]
+    fn test_fwenv_read_falls_back_to_second_when_first_unreadable() {
+        const SIZE: usize = 0x1000;
+        let copy2 = TempFile::new("copy2", &make_redundant_block(&[("foo", "two")], 9, SIZE));
+        let config = Config {
+            line1: ConfigLine {
+                devname: "testfiles/does-not-exist".to_string(),
+                start: 0,
+                size: SIZE,
+                endianness: Endianness::Little,
+            },
+            line2: Some(copy2.line(SIZE)),
+        };
+        let env = FwEnv::read(&config).unwrap();
+        assert_eq!(env.active_copy, ActiveCopy::Second(9));
+        assert_eq!(env.var("foo").unwrap(), "two");
+    }
+
+    #[test]
+    fn test_fwenv_read_falls_back_to_first_when_second_has_bad_crc() {
+        const SIZE: usize = 0x1000;
+        let copy1 = TempFile::new("copy1", &make_redundant_block(&[("foo", "one")], 1, SIZE));
+        let mut corrupt = make_redundant_block(&[("foo", "two")], 2, SIZE);
+        corrupt[0] ^= 0xff;
+        let copy2 = TempFile::new("copy2", &corrupt);
+        let config = Config {
+            line1: copy1.line(SIZE),
+            line2: Some(copy2.line(SIZE)),
+        };
+        let env = FwEnv::read(&config).unwrap();
+        assert_eq!(env.active_copy, ActiveCopy::First(1));
+        assert_eq!(env.var("foo").unwrap(), "one");
+    }
+
+    #[test]
+    fn test_fwenv_read_fails_when_both_copies_bad() {
+        const SIZE: usize = 0x1000;
+        let mut corrupt1 = make_redundant_block(&[("foo", "one")], 1, SIZE);
+        corrupt1[0] ^= 0xff;
+        let mut corrupt2 = make_redundant_block(&[("foo", "two")], 2, SIZE);
+        corrupt2[0] ^= 0xff;
+        let copy1 = TempFile::new("copy1", &corrupt1);
+        let copy2 = TempFile::new("copy2", &corrupt2);
+        let config = Config {
+            line1: copy1.line(SIZE),
+            line2: Some(copy2.line(SIZE)),
+        };
+        assert!(matches!(FwEnv::read(&config), Err(FwError::BadCrc)));
+    }
+
+    #[test]
+    fn test_fwenv_typed_accessors() {
+        const SIZE: usize = 0x1000;
+        let copy = TempFile::new(
+            "copy",
+            &make_redundant_block(&[("foo", "bar"), ("baz", "quux")], 0, SIZE),
+        );
+        let env = FwEnv::read(&Config {
+            line1: copy.line(SIZE),
+            line2: Some(copy.line(SIZE)),
+        })
+        .unwrap();
+
+        assert_eq!(env.var("foo").unwrap(), "bar");
+        assert!(matches!(env.var("missing"), Err(FwError::NotPresent)));
+
+        let mut vars: Vec<_> = env.vars_lossy().collect();
+        vars.sort();
+        assert_eq!(
+            vars,
+            vec![
+                ("baz".to_string(), "quux".to_string()),
+                ("foo".to_string(), "bar".to_string()),
+            ]
+        );
+
+        let mut raw: Vec<_> = env.iter().collect();
+        raw.sort();
+        assert_eq!(raw, vec![(&b"baz"[..], &b"quux"[..]), (&b"foo"[..], &b"bar"[..])]);
+
+        std::env::set_var("foo", "overlaid");
+        assert_eq!(env.var_overlaid("foo").unwrap(), "overlaid");
+        std::env::remove_var("foo");
+        assert_eq!(env.var_overlaid("foo").unwrap(), "bar");
+    }
+
+    #[test]
+    fn test_fwenv_write_read_round_trip() {
+        const SIZE: usize = 0x1000;
+        let device = TempFile::new("device", &vec![0; SIZE]);
+        let config = Config {
+            line1: device.line(SIZE),
+            line2: None,
+        };
+
+        let mut env = FwEnv {
+            vars: Vec::new(),
+            active_copy: ActiveCopy::Single,
+        };
+        env.set_var(&b"foo"[..], &b"bar"[..]);
+        env.set_var(&b"baz"[..], &b"qux"[..]);
+        env.set_var(&b"foo"[..], &b"overwritten"[..]);
+        env.remove_var(&b"baz"[..]);
+        env.write(&config).unwrap();
+
+        let read_back = FwEnv::read(&config).unwrap();
+        assert_eq!(read_back.find_var(&b"foo"[..]), Some(&b"overwritten"[..]));
+        assert_eq!(read_back.find_var(&b"baz"[..]), None);
+    }
+
+    #[test]
+    fn test_fwenv_write_rejects_env_too_large_for_block() {
+        const SIZE: usize = ENV_SIMPLE_SIZE + 4;
+        let device = TempFile::new("device_small", &[0; SIZE]);
+        let config = Config {
+            line1: device.line(SIZE),
+            line2: None,
+        };
+
+        let mut env = FwEnv {
+            vars: Vec::new(),
+            active_copy: ActiveCopy::Single,
+        };
+        env.set_var(&b"foo"[..], &b"a value far too long to fit"[..]);
+        assert!(matches!(
+            env.write(&config),
+            Err(FwError::EnvTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn test_fwenv_write_read_round_trip_big_endian() {
+        const SIZE: usize = 0x1000;
+        let device = TempFile::new("device_be", &vec![0; SIZE]);
+        let mut line = device.line(SIZE);
+        line.endianness = Endianness::Big;
+        let config = Config {
+            line1: line,
+            line2: None,
+        };
+
+        let mut env = FwEnv {
+            vars: Vec::new(),
+            active_copy: ActiveCopy::Single,
+        };
+        env.set_var(&b"foo"[..], &b"bar"[..]);
+        env.write(&config).unwrap();
+
+        // The CRC header must actually land as big-endian bytes on flash,
+        // not just happen to round-trip through the same code path.
+        let raw = std::fs::read(&device.0).unwrap();
+        let crc = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+        let mut digest = crc.digest();
+        digest.update(&raw[ENV_SIMPLE_SIZE..]);
+        assert_eq!(&raw[..4], &digest.finalize().to_be_bytes());
+
+        let read_back = FwEnv::read(&config).unwrap();
+        assert_eq!(read_back.find_var(&b"foo"[..]), Some(&b"bar"[..]));
+
+        // A little-endian reader should reject it: the same bytes decode
+        // to a different (wrong) reference CRC.
+        let mut le_line = device.line(SIZE);
+        le_line.endianness = Endianness::Little;
+        let le_config = Config {
+            line1: le_line,
+            line2: None,
+        };
+        assert!(matches!(FwEnv::read(&le_config), Err(FwError::BadCrc)));
+    }
 }
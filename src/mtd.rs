@@ -0,0 +1,159 @@
+// MTD/NAND backend for `FwEnv::read_block`/`FwEnv::write`: when the device
+// is an MTD char device, skip erase blocks the kernel has flagged bad, the
+// same way U-Boot reads and writes a NAND environment.
+
+use std::io::prelude::*;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use crate::FwError;
+
+// struct mtd_info_user, from <mtd/mtd-abi.h>.
+#[repr(C)]
+#[derive(Debug, Default)]
+struct MtdInfoUser {
+    type_: u8,
+    flags: u32,
+    size: u32,
+    erasesize: u32,
+    writesize: u32,
+    oobsize: u32,
+    padding: u64,
+}
+
+// -1 means the ioctl failed and errno was set; anything else is a valid
+// return value (MEMGETBADBLOCK notably uses 0/1 to mean good/bad).
+fn cvt(ret: libc::c_int) -> Result<libc::c_int, FwError> {
+    if ret == -1 {
+        Err(FwError::Io(std::io::Error::last_os_error()))
+    } else {
+        Ok(ret)
+    }
+}
+
+fn mem_get_info(fd: libc::c_int) -> Result<MtdInfoUser, FwError> {
+    let req = nix::request_code_read!(b'M', 1, std::mem::size_of::<MtdInfoUser>());
+    let mut info = MtdInfoUser::default();
+    cvt(unsafe { libc::ioctl(fd, req as _, &mut info as *mut MtdInfoUser) })?;
+    Ok(info)
+}
+
+fn mem_get_bad_block(fd: libc::c_int, offset: i64) -> Result<bool, FwError> {
+    let req = nix::request_code_write!(b'M', 11, std::mem::size_of::<i64>());
+    let ret = cvt(unsafe { libc::ioctl(fd, req as _, &offset as *const i64) })?;
+    Ok(ret != 0)
+}
+
+pub(crate) fn is_mtd_char_device<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref()
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.starts_with("mtd") && !name.contains("block"))
+        .unwrap_or(false)
+}
+
+// Walks erase blocks from `offset`, skipping any one `is_bad` flags, and
+// returns the `(block_start, len)` windows that together cover `size` good
+// bytes. Pulled out of `read_block`/`write_block` so the skip logic itself
+// can be unit-tested against a fake bad-block predicate, without a real
+// MTD device to drive the ioctls.
+fn plan_blocks(
+    offset: usize,
+    size: usize,
+    erasesize: usize,
+    mut is_bad: impl FnMut(usize) -> Result<bool, FwError>,
+) -> Result<Vec<(usize, usize)>, FwError> {
+    let mut plan = Vec::new();
+    let mut block_start = offset;
+    let mut remaining = size;
+    while remaining > 0 {
+        if is_bad(block_start)? {
+            block_start += erasesize;
+            continue;
+        }
+        let want = std::cmp::min(erasesize, remaining);
+        plan.push((block_start, want));
+        remaining -= want;
+        block_start += erasesize;
+    }
+    Ok(plan)
+}
+
+pub(crate) fn read_block<P: AsRef<Path>>(
+    path: P,
+    offset: usize,
+    size: usize,
+) -> Result<Vec<u8>, FwError> {
+    let mut file = std::fs::File::open(path)?;
+    let info = mem_get_info(file.as_raw_fd())?;
+    let erasesize = (info.erasesize as usize).max(1);
+    let fd = file.as_raw_fd();
+
+    let plan = plan_blocks(offset, size, erasesize, |block_start| {
+        mem_get_bad_block(fd, block_start as i64)
+    })?;
+    let mut out = Vec::with_capacity(size);
+    for (block_start, want) in plan {
+        let mut buf = vec![0; want];
+        file.seek(std::io::SeekFrom::Start(block_start as u64))?;
+        file.read_exact(&mut buf)?;
+        out.extend_from_slice(&buf);
+    }
+    Ok(out)
+}
+
+// Sibling of `read_block`: writes `data` starting at `offset`, skipping
+// the same bad erase blocks `read_block` would skip, so a block the kernel
+// has already flagged bad never gets written to.
+pub(crate) fn write_block<P: AsRef<Path>>(path: P, offset: usize, data: &[u8]) -> Result<(), FwError> {
+    let mut file = std::fs::OpenOptions::new().write(true).open(path)?;
+    let info = mem_get_info(file.as_raw_fd())?;
+    let erasesize = (info.erasesize as usize).max(1);
+    let fd = file.as_raw_fd();
+
+    let plan = plan_blocks(offset, data.len(), erasesize, |block_start| {
+        mem_get_bad_block(fd, block_start as i64)
+    })?;
+    let mut written = 0;
+    for (block_start, want) in plan {
+        file.seek(std::io::SeekFrom::Start(block_start as u64))?;
+        file.write_all(&data[written..written + want])?;
+        written += want;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan_blocks_skips_bad_blocks() {
+        // Blocks at 0x2000 and 0x6000 are bad; erasesize 0x1000, 3 good
+        // blocks' worth of data requested starting at 0.
+        let bad = [0x2000, 0x6000];
+        let plan = plan_blocks(0, 0x3000, 0x1000, |block_start| {
+            Ok(bad.contains(&block_start))
+        })
+        .unwrap();
+        assert_eq!(
+            plan,
+            vec![(0x0000, 0x1000), (0x1000, 0x1000), (0x3000, 0x1000)]
+        );
+    }
+
+    #[test]
+    fn test_plan_blocks_last_window_is_partial() {
+        let plan = plan_blocks(0, 0x1800, 0x1000, |_| Ok(false)).unwrap();
+        assert_eq!(plan, vec![(0x0000, 0x1000), (0x1000, 0x0800)]);
+    }
+
+    #[test]
+    fn test_plan_blocks_propagates_bad_block_check_errors() {
+        let err = plan_blocks(0, 0x1000, 0x1000, |_| {
+            Err(FwError::Io(std::io::Error::from(std::io::ErrorKind::Other)))
+        })
+        .unwrap_err();
+        assert!(matches!(err, FwError::Io(_)));
+    }
+}